@@ -1,27 +1,120 @@
 #![feature(int_roundings)]
 use bytes::{BytesMut, BufMut, Bytes, Buf};
 use bytes_cast::{BytesCast, unaligned};
+use std::time::{SystemTime, UNIX_EPOCH};
 //https://gitlab.nist.gov/gitlab/qsg/hw-ipcores/packetizer/-/blob/master/doc/packetformat.md
 
 const HEADER_MAGIC: u32  = 0x810b00ff;
 const HEADER_VERSION: u8 = 1u8;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketError {
+    UnexpectedEof { needed: usize, got: usize },
+    UnknownTag(u8),
+    BadMagic(u32),
+    UnsupportedVersion(u8),
+    UnsupportedTlvLength(u8),
+    HeaderLengthMismatch,
+    TimestampOverflow(u64),
+    TlvValueTooLong(usize),
+    HeaderTooLong(usize),
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(self: &PacketError, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PacketError::UnexpectedEof { needed, got } =>
+                write!(f, "unexpected end of buffer: needed {} bytes, got {}", needed, got),
+            PacketError::UnknownTag(tag) => write!(f, "tlv tag 0x{:x?} not implemented", tag),
+            PacketError::BadMagic(magic) => write!(f, "bad magic 0x{:x?}", magic),
+            PacketError::UnsupportedVersion(version) => write!(f, "unsupported header version {}", version),
+            PacketError::UnsupportedTlvLength(len) =>
+                write!(f, "tlv length {} (8-byte units) not supported", len),
+            PacketError::HeaderLengthMismatch => write!(f, "headerlength shorter than fixed header"),
+            PacketError::TimestampOverflow(nanos) =>
+                write!(f, "unix time {} ns does not fit in a 48-bit CUC timestamp", nanos),
+            PacketError::TlvValueTooLong(value_len) =>
+                write!(f, "tlv value of {} bytes does not fit in the 1-byte len8bytes field (max 2038)", value_len),
+            PacketError::HeaderTooLong(headerlength) =>
+                write!(f, "header of {} bytes does not fit in the 1-byte headerlength field (max 255)", headerlength),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+// A CCSDS CUC-style timestamp: whole seconds since the Unix epoch plus a
+// 16-bit sub-second counter, chosen so the pair packs exactly into the
+// TLV's 48-bit Timestamp field (32 + 16 bits).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Timestamp {
+    seconds: u32,
+    subseconds: u16,
+}
+
+const TIMESTAMP_SUBSECOND_TICKS: u64 = 1 << 16;
+
+impl Timestamp {
+    fn from_unix_nanos(nanos: u64) -> Result<Timestamp, PacketError> {
+        let seconds: u32 = (nanos / 1_000_000_000).try_into()
+            .map_err(|_| PacketError::TimestampOverflow(nanos))?;
+        let subsec_nanos = nanos % 1_000_000_000;
+        let subseconds = (subsec_nanos * TIMESTAMP_SUBSECOND_TICKS / 1_000_000_000) as u16;
+        Ok(Timestamp { seconds, subseconds })
+    }
+
+    fn to_unix_nanos(self: &Timestamp) -> u64 {
+        u64::from(self.seconds) * 1_000_000_000
+            + u64::from(self.subseconds) * 1_000_000_000 / TIMESTAMP_SUBSECOND_TICKS
+    }
+
+    fn now() -> Timestamp {
+        let nanos: u64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock is before the Unix epoch")
+            .as_nanos()
+            .try_into()
+            .expect("current unix time fits in a u64 number of nanoseconds");
+        Timestamp::from_unix_nanos(nanos)
+            .expect("current unix time fits in a 48-bit CUC timestamp until year 2106")
+    }
+
+    fn to_48bits(self: &Timestamp) -> u64 {
+        (u64::from(self.seconds) << 16) | u64::from(self.subseconds)
+    }
+
+    fn from_48bits(v: u64) -> Timestamp {
+        Timestamp {
+            seconds: (v >> 16) as u32,
+            subseconds: (v & 0xFFFF) as u16,
+        }
+    }
+}
+
 struct PacketMaker {
     srcid: u32,
     seqno: u32,
-    tlvs: Vec<TLV> // this should be only static tlvs
+    tlvs: Vec<TLV>, // this should be only static tlvs
+    include_timestamp: bool,
 }
 
 impl PacketMaker {
-    fn new(srcid: u32, tlvs: Vec<TLV>) -> PacketMaker {
-        PacketMaker { srcid: srcid, seqno: 0, tlvs: tlvs }
+    fn new(srcid: u32, tlvs: Vec<TLV>, include_timestamp: bool) -> PacketMaker {
+        PacketMaker { srcid, seqno: 0, tlvs, include_timestamp }
     }
 
-    // this should take dynamic tlvs, like timestamp
-    fn make(mut self: &mut PacketMaker, payload: Vec<u8>) -> Bytes {
-        let len_tlvs: usize = self.tlvs.iter().map(|x| x.len()).sum();
-        let headerlength = len_tlvs + HeaderNoTLV::len(); 
-        let payloadlength: u16 = payload.len().try_into().unwrap();       
+    fn make(self: &mut PacketMaker, payload: Vec<u8>, dynamic_tlvs: &[TLV]) -> Result<Bytes, PacketError> {
+        let timestamp_tlv = self.include_timestamp.then(|| TLV::Timestamp(Timestamp::now()));
+        let all_tlvs: Vec<&TLV> = self.tlvs.iter()
+            .chain(dynamic_tlvs.iter())
+            .chain(timestamp_tlv.iter())
+            .collect();
+        let len_tlvs: usize = all_tlvs.iter().try_fold(0usize, |acc, x| x.len().map(|l| acc + l))?;
+        let headerlength = len_tlvs + HeaderNoTLV::len();
+        if headerlength > usize::from(u8::MAX) {
+            return Err(PacketError::HeaderTooLong(headerlength));
+        }
+        let payloadlength: u16 = payload.len().try_into().unwrap();
         let header_no_tlv = HeaderNoTLV{
             version: HEADER_VERSION,
             headerlength: headerlength.try_into().unwrap(),
@@ -29,15 +122,15 @@ impl PacketMaker {
             magic: HEADER_MAGIC.into(),
             srcid: self.srcid.into(),
             seqno: self.seqno.into()
-        };           
+        };
         let mut buf = BytesMut::with_capacity(headerlength+payload.len());
         buf.put_slice(header_no_tlv.as_bytes());
-        for tlv in self.tlvs.iter() {
-            tlv.write_to(&mut buf)
+        for tlv in all_tlvs.iter() {
+            tlv.write_to(&mut buf)?
         }
         buf.put_slice(payload.as_bytes());
         self.seqno +=1;
-        return buf.freeze()
+        Ok(buf.freeze())
     }
 
 }
@@ -74,76 +167,288 @@ struct Header {
 }
 
 impl Header{
-    fn len(self: &Header) -> usize {
-        let len_tlvs: usize = self.tlvs.iter().map(|x| x.len()).sum();
-        len_tlvs + HeaderNoTLV::len()
+    fn len(self: &Header) -> Result<usize, PacketError> {
+        let len_tlvs: usize = self.tlvs.iter().try_fold(0usize, |acc, x| x.len().map(|l| acc + l))?;
+        let headerlength = len_tlvs + HeaderNoTLV::len();
+        if headerlength > usize::from(u8::MAX) {
+            return Err(PacketError::HeaderTooLong(headerlength));
+        }
+        Ok(headerlength)
     }
-    fn write_to(self: &Header, buf: &mut BytesMut) {
+    fn write_to(self: &Header, buf: &mut BytesMut) -> Result<(), PacketError> {
         let mut header_no_tlv_correct_len = self.header_no_tlv.clone();
-        header_no_tlv_correct_len.headerlength = u8::try_from(self.len()).unwrap();
+        header_no_tlv_correct_len.headerlength = u8::try_from(self.len()?).unwrap();
         buf.put_slice(header_no_tlv_correct_len.as_bytes());
         for tlv in self.tlvs.iter() {
-            tlv.write_to(buf)
+            tlv.write_to(buf)?
         }
+        Ok(())
     }
-    fn as_bytes(self: &Header) -> Vec<u8> {
-        let mut buf = BytesMut::with_capacity(self.len());
-        self.write_to(&mut buf);
-        return buf.to_vec()
-    }   
-    fn from_bytes(buf: &[u8]) -> Header {
-        let mut header_no_tlv: HeaderNoTLV;
-        let result = HeaderNoTLV::from_bytes(buf);
-        let (header_no_tlv, rest) = result.unwrap();
-        let tlv_len = usize::from(header_no_tlv.headerlength)-HeaderNoTLV::len();
-        let tlvs = TLV::vec_from_bytes(&rest[..tlv_len]);
-        Header{header_no_tlv: *header_no_tlv, tlvs: tlvs}
+    fn as_bytes(self: &Header) -> Result<Vec<u8>, PacketError> {
+        let mut buf = BytesMut::with_capacity(self.len()?);
+        self.write_to(&mut buf)?;
+        Ok(buf.to_vec())
+    }
+    fn from_bytes(buf: &[u8]) -> Result<Header, PacketError> {
+        let (header_no_tlv, rest) = HeaderNoTLV::from_bytes(buf)
+            .map_err(|_| PacketError::UnexpectedEof { needed: HeaderNoTLV::len(), got: buf.len() })?;
+        let headerlength = usize::from(header_no_tlv.headerlength);
+        let tlv_len = headerlength.checked_sub(HeaderNoTLV::len())
+            .ok_or(PacketError::HeaderLengthMismatch)?;
+        if rest.len() < tlv_len {
+            return Err(PacketError::UnexpectedEof { needed: tlv_len, got: rest.len() });
+        }
+        let tlvs = TLV::vec_from_bytes(&rest[..tlv_len])?;
+        Ok(Header{header_no_tlv: *header_no_tlv, tlvs: tlvs})
     }
 
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum TLV {
-    Timestamp(u64),
-    Null,
-    Payloadshape([u16;3]),
-    ChannelOffset(u32),
-    PayloadLabel6Char([u8;6])
+// The read-path counterpart to PacketMaker: validates a buffer holds a
+// well-formed packet (magic, version, and enough bytes for the declared
+// payload) and hands back the parsed Header alongside a borrowed slice of
+// exactly the payload bytes.
+struct PacketReader;
+
+impl PacketReader {
+    fn parse(buf: &[u8]) -> Result<(Header, &[u8]), PacketError> {
+        let header = Header::from_bytes(buf)?;
+        let magic = header.header_no_tlv.magic.get();
+        if magic != HEADER_MAGIC {
+            return Err(PacketError::BadMagic(magic));
+        }
+        if header.header_no_tlv.version != HEADER_VERSION {
+            return Err(PacketError::UnsupportedVersion(header.header_no_tlv.version));
+        }
+        let headerlength = usize::from(header.header_no_tlv.headerlength);
+        let payloadlength = usize::from(header.header_no_tlv.payloadlength.get());
+        let framelength = headerlength + payloadlength;
+        if buf.len() < framelength {
+            return Err(PacketError::UnexpectedEof { needed: framelength, got: buf.len() });
+        }
+        Ok((header, &buf[headerlength..framelength]))
+    }
 }
 
-impl TLV {
-    fn len8bytes(self: &TLV) -> u8 {
-        let len8bytes: u8 = match self {
-            TLV::Timestamp(_) => 1,
-            TLV::Null => 1,
-            TLV::Payloadshape(_) => 1,
-            TLV::ChannelOffset(_) => 1,
-            TLV::PayloadLabel6Char(_) => 1,
+// How a seqno from SeqnoTracker::check_seqno compares to the last one seen
+// from that srcid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SeqnoStatus {
+    InOrder,
+    Duplicate,
+    Gap { missed: u32 },
+}
+
+// Tracks the last seen seqno per srcid so PacketReader callers can spot
+// duplicated or dropped packets. A seqno at or behind the last one seen,
+// including one that arrives out of order, is reported as Duplicate, and
+// every checked seqno becomes the new last seqno for its srcid.
+struct SeqnoTracker {
+    last_seqno: std::collections::HashMap<u32, u32>,
+}
+
+impl SeqnoTracker {
+    fn new() -> SeqnoTracker {
+        SeqnoTracker { last_seqno: std::collections::HashMap::new() }
+    }
+
+    fn check_seqno(self: &mut SeqnoTracker, srcid: u32, seqno: u32) -> SeqnoStatus {
+        let status = match self.last_seqno.get(&srcid) {
+            None => SeqnoStatus::InOrder,
+            Some(&last) => {
+                // compare as a signed distance so the classification stays
+                // consistent across a u32 wraparound instead of just the
+                // InOrder case
+                let diff = seqno.wrapping_sub(last) as i32;
+                if diff <= 0 {
+                    SeqnoStatus::Duplicate
+                } else if diff == 1 {
+                    SeqnoStatus::InOrder
+                } else {
+                    SeqnoStatus::Gap { missed: (diff - 1) as u32 }
+                }
+            }
         };
-        return len8bytes
+        self.last_seqno.insert(srcid, seqno);
+        status
+    }
+}
+
+// A read cursor over a borrowed buffer. Every decode_* method advances
+// `offset` only when it succeeds, so a failed read leaves the cursor where
+// it was and the same bytes can be retried once more of them have arrived.
+struct Decoder<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder { buf, offset: 0 }
+    }
+
+    fn remaining(self: &Decoder<'a>) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    fn decode_u8(self: &mut Decoder<'a>) -> Option<u8> {
+        let v = *self.buf.get(self.offset)?;
+        self.offset += 1;
+        Some(v)
+    }
+
+    fn decode_uint(self: &mut Decoder<'a>, n: usize) -> Option<u64> {
+        if n == 0 || n > 8 || self.remaining() < n {
+            return None;
+        }
+        let mut v: u64 = 0;
+        for b in &self.buf[self.offset..self.offset+n] {
+            v = (v << 8) | u64::from(*b);
+        }
+        self.offset += n;
+        Some(v)
     }
 
-    fn len(self: &TLV) -> usize {
-        return (self.len8bytes()*8).into()
+    fn decode_u16be(self: &mut Decoder<'a>) -> Option<u16> {
+        self.decode_uint(2).map(|v| v as u16)
     }
 
-    fn tag (self: &TLV) -> u8 {
-        return  match self {
+    fn decode_u32be(self: &mut Decoder<'a>) -> Option<u32> {
+        self.decode_uint(4).map(|v| v as u32)
+    }
+
+    fn skip(self: &mut Decoder<'a>, n: usize) -> Option<()> {
+        if self.remaining() < n {
+            return None;
+        }
+        self.offset += n;
+        Some(())
+    }
+
+    fn decode_remainder(self: &mut Decoder<'a>) -> &'a [u8] {
+        let rest = &self.buf[self.offset..];
+        self.offset = self.buf.len();
+        rest
+    }
+}
+
+// Reassembles packets from a byte stream that may deliver them in
+// arbitrarily small fragments, e.g. reads off a TCP socket. Bytes handed to
+// `feed` are buffered until a whole frame (`headerlength + payloadlength`
+// bytes, per the HeaderNoTLV preamble) has arrived; only then is a Header
+// and its payload produced, and any trailing bytes are kept as the start of
+// the next frame. A declared frame length that's too small to even hold the
+// preamble that produced it can't be waited out, so `feed` drops one byte
+// and returns Err instead of buffering it forever; callers should keep
+// calling `feed` (with no new bytes, if none have arrived) to resync.
+struct IncrementalDecoder {
+    buf: BytesMut,
+}
+
+impl IncrementalDecoder {
+    fn new() -> IncrementalDecoder {
+        IncrementalDecoder { buf: BytesMut::new() }
+    }
+
+    fn feed(self: &mut IncrementalDecoder, bytes: &[u8]) -> Result<Option<(Header, Bytes)>, PacketError> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() < HeaderNoTLV::len() {
+            return Ok(None);
+        }
+        let mut dec = Decoder::new(&self.buf);
+        dec.skip(1).expect("checked buf.len() >= HeaderNoTLV::len() above"); // version
+        let headerlength = dec.decode_u8().expect("checked buf.len() >= HeaderNoTLV::len() above") as usize;
+        let payloadlength = dec.decode_u16be().expect("checked buf.len() >= HeaderNoTLV::len() above") as usize;
+        let framelength = headerlength + payloadlength;
+        if framelength < HeaderNoTLV::len() {
+            // there's no valid frame boundary to wait for here, so drop one
+            // byte and let the next feed attempt resync instead of
+            // buffering these bytes forever
+            self.buf.advance(1);
+            return Err(PacketError::HeaderLengthMismatch);
+        }
+        if self.buf.len() < framelength {
+            return Ok(None);
+        }
+        let frame = self.buf.split_to(framelength).freeze();
+        let header = Header::from_bytes(&frame)?;
+        let payload = frame.slice(headerlength..framelength);
+        Ok(Some((header, payload)))
+    }
+}
+
+// How a TLV's tag and value serialize as an 8-byte-aligned on-wire TLV;
+// `write_tlv`'s default implementation handles the tag/length/padding, only
+// `tag`/`value_len`/`write_value` vary per TLV.
+trait WritableTlv {
+    fn tag(&self) -> u8;
+    fn value_len(&self) -> usize;
+    fn write_value(&self, buf: &mut BytesMut);
+
+    fn len8bytes(&self) -> Result<u8, PacketError> {
+        let value_len = self.value_len();
+        u8::try_from((value_len + 2).div_ceil(8))
+            .map_err(|_| PacketError::TlvValueTooLong(value_len))
+    }
+
+    fn write_tlv(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        let len8bytes = self.len8bytes()?;
+        buf.put_u8(self.tag());
+        buf.put_u8(len8bytes);
+        let before = buf.len();
+        self.write_value(buf);
+        let value_slot = usize::from(len8bytes) * 8 - 2;
+        let written = buf.len() - before;
+        if written < value_slot {
+            buf.put_bytes(0u8, value_slot - written);
+        }
+        Ok(())
+    }
+}
+
+// Read-side counterpart of WritableTlv: a decoded TLV's tag, on-wire length
+// and raw value bytes, before (or instead of) decoding it into a TLV.
+#[allow(dead_code)]
+trait GenericTlv {
+    fn tag(&self) -> u8;
+    fn len(&self) -> usize;
+    fn value_bytes(&self) -> &[u8];
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TLV {
+    Timestamp(Timestamp),
+    Null,
+    Payloadshape([u16;3]),
+    ChannelOffset(u32),
+    PayloadLabel6Char(Vec<u8>)
+}
+
+impl WritableTlv for TLV {
+    fn tag(self: &TLV) -> u8 {
+        match self {
             TLV::Timestamp(_) => 0x11,
             TLV::Null => 0x00,
             TLV::Payloadshape(_) => 0x22,
             TLV::ChannelOffset(_) => 0x23,
             TLV::PayloadLabel6Char(_) => 0x29
-            }       
+        }
     }
 
-    // promises to write a multiple of 8 bytes
-    fn write_to(self: &TLV, buf: &mut BytesMut) {
-        buf.put_u8(self.tag());
-        buf.put_u8(self.len8bytes());
+    fn value_len(self: &TLV) -> usize {
+        match self {
+            TLV::Timestamp(_) => 6,
+            TLV::Null => 6,
+            TLV::Payloadshape(_) => 6,
+            TLV::ChannelOffset(_) => 6,
+            TLV::PayloadLabel6Char(x) => x.len(),
+        }
+    }
+
+    fn write_value(self: &TLV, buf: &mut BytesMut) {
         match self {
             TLV::Timestamp(x) => {
-                let x = unaligned::U64Be::from(*x);
+                let x = unaligned::U64Be::from(x.to_48bits());
                 let b = x.as_bytes();
                 buf.put_slice(&b[2..8]);
             },
@@ -151,88 +456,225 @@ impl TLV {
                 buf.put_bytes(0u8,6);
             },
             TLV::Payloadshape(shape) => {
-                for (i,x) in shape.iter().enumerate() {
+                for x in shape.iter() {
                     let y : unaligned::U16Be = (*x).into();
                     let y = y.as_bytes();
                     buf.put_u8(y[0]);
                     buf.put_u8(y[1]);
-                }               
+                }
             },
             TLV::ChannelOffset(x) => {
                 buf.put_bytes(0u8,2);
                 buf.put_u32(*x);
             },
             TLV::PayloadLabel6Char(x) => {
-                buf.put_slice(x.as_bytes());
+                buf.put_slice(x);
             }
-
         }
     }
+}
+
+impl TLV {
+    fn len8bytes(self: &TLV) -> Result<u8, PacketError> {
+        WritableTlv::len8bytes(self)
+    }
+
+    fn len(self: &TLV) -> Result<usize, PacketError> {
+        Ok(usize::from(self.len8bytes()?) * 8)
+    }
 
-    fn try_from_bytes(buf: &[u8]) -> (Option<TLV>, &[u8]) {
+    // promises to write a multiple of 8 bytes, or return Err if the value
+    // doesn't fit in the 1-byte len8bytes field
+    fn write_to(self: &TLV, buf: &mut BytesMut) -> Result<(), PacketError> {
+        self.write_tlv(buf)
+    }
+
+    fn try_from_bytes(buf: &[u8]) -> Result<(Option<TLV>, &[u8]), PacketError> {
+        if buf.is_empty() {
+            return Ok((None, buf))
+        }
         if buf.len() < 8 {
-            return (None, buf)
+            return Err(PacketError::UnexpectedEof { needed: 8, got: buf.len() })
         }
         let tag = buf[0];
+        let len8bytes = buf[1];
+        let total_len = usize::from(len8bytes) * 8;
+        if total_len < 8 {
+            return Err(PacketError::UnsupportedTlvLength(len8bytes))
+        }
+        if buf.len() < total_len {
+            return Err(PacketError::UnexpectedEof { needed: total_len, got: buf.len() })
+        }
+        let value = &buf[2..total_len];
         let mut bytes = Bytes::copy_from_slice(&buf[..8]);
         let tlv =  match tag {
-            0x00 => TLV::Null,
+            0x00 => {
+                if len8bytes != 1 { return Err(PacketError::UnsupportedTlvLength(len8bytes)) }
+                TLV::Null
+            },
             0x11 => {
+                if len8bytes != 1 { return Err(PacketError::UnsupportedTlvLength(len8bytes)) }
                 let x = bytes.get_u64();
                 let x = x & 0x0000FFFFFFFFFFFF; // ignore the tag and tlv length
-                TLV::Timestamp(x)
+                TLV::Timestamp(Timestamp::from_48bits(x))
             },
             0x22 => {
+                if len8bytes != 1 { return Err(PacketError::UnsupportedTlvLength(len8bytes)) }
                 bytes.advance(2);
                 let x = [bytes.get_u16(), bytes.get_u16(), bytes.get_u16()];
                 TLV::Payloadshape(x)
             },
             0x23 => {
+                if len8bytes != 1 { return Err(PacketError::UnsupportedTlvLength(len8bytes)) }
                 bytes.advance(4);
                 TLV::ChannelOffset(bytes.get_u32())
             },
-            0x29 => {
-                let nbytes = buf[1];// check len in bytes
-                if nbytes == 1 {
-                    let a: [u8;6] = buf[2..8].try_into().unwrap();
-                    TLV::PayloadLabel6Char(a)} 
-                else {
-                    panic!("TLVS longer than 1 byte not supported")
-                    }
-            }
-            x => panic!("tlv tag 0x{:x?} not implemented",x),
+            0x29 => TLV::PayloadLabel6Char(value.to_vec()),
+            x => return Err(PacketError::UnknownTag(x)),
         };
-        return (Some(tlv), &buf[8..])
+        return Ok((Some(tlv), &buf[total_len..]))
     }
 
-    fn vec_from_bytes(buf: &[u8]) -> Vec<TLV> {
+    fn vec_from_bytes(buf: &[u8]) -> Result<Vec<TLV>, PacketError> {
         let mut v = Vec::new();
         let mut tlv: Option<TLV>;
         let mut buf = buf;
         loop {
-            (tlv, buf) = TLV::try_from_bytes(buf);
+            (tlv, buf) = TLV::try_from_bytes(buf)?;
             match tlv {
                 None => break,
                 Some(x) => v.push(x)
             }
         };
-        v
+        Ok(v)
     }
 
     #[allow(dead_code)]
-    fn as_bytes(self: &TLV) -> Vec<u8> {
-        let mut buf = BytesMut::with_capacity(self.len());
-        self.write_to(&mut buf);
-        return buf.to_vec()
+    fn as_bytes(self: &TLV) -> Result<Vec<u8>, PacketError> {
+        let mut buf = BytesMut::with_capacity(self.len()?);
+        self.write_to(&mut buf)?;
+        Ok(buf.to_vec())
     }
 
 }
 
+// A decoded TLV that borrows straight from the packet buffer rather than
+// owning its value bytes, for PacketView's zero-allocation iteration.
+struct TlvRef<'a> {
+    tag: u8,
+    len8bytes: u8,
+    value: &'a [u8],
+}
+
+impl<'a> GenericTlv for TlvRef<'a> {
+    fn tag(self: &TlvRef<'a>) -> u8 {
+        self.tag
+    }
+    fn len(self: &TlvRef<'a>) -> usize {
+        usize::from(self.len8bytes) * 8
+    }
+    fn value_bytes(self: &TlvRef<'a>) -> &[u8] {
+        self.value
+    }
+}
+
+struct TlvIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = Result<TlvRef<'a>, PacketError>;
+
+    fn next(self: &mut TlvIter<'a>) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        if self.buf.len() < 2 {
+            let err = PacketError::UnexpectedEof { needed: 2, got: self.buf.len() };
+            self.buf = &[];
+            return Some(Err(err));
+        }
+        let tag = self.buf[0];
+        let len8bytes = self.buf[1];
+        let total_len = usize::from(len8bytes) * 8;
+        if total_len < 8 {
+            self.buf = &[];
+            return Some(Err(PacketError::UnsupportedTlvLength(len8bytes)));
+        }
+        if self.buf.len() < total_len {
+            let err = PacketError::UnexpectedEof { needed: total_len, got: self.buf.len() };
+            self.buf = &[];
+            return Some(Err(err));
+        }
+        let value = &self.buf[2..total_len];
+        self.buf = &self.buf[total_len..];
+        Some(Ok(TlvRef { tag, len8bytes, value }))
+    }
+}
+
+// Like PacketReader, but borrows instead of allocating a Header: each field
+// is read directly out of `buf` with checked slicing, so a short or
+// truncated buffer comes back as None/Err instead of an indexing panic.
+struct PacketView<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> PacketView<'a> {
+    fn new(buf: &'a [u8]) -> PacketView<'a> {
+        PacketView { buf }
+    }
+
+    fn version(self: &PacketView<'a>) -> Option<u8> {
+        self.buf.get(0).copied()
+    }
+
+    fn headerlength(self: &PacketView<'a>) -> Option<u8> {
+        self.buf.get(1).copied()
+    }
+
+    fn payloadlength(self: &PacketView<'a>) -> Option<u16> {
+        Some(u16::from_be_bytes(self.buf.get(2..4)?.try_into().unwrap()))
+    }
+
+    fn magic(self: &PacketView<'a>) -> Option<u32> {
+        Some(u32::from_be_bytes(self.buf.get(4..8)?.try_into().unwrap()))
+    }
+
+    fn srcid(self: &PacketView<'a>) -> Option<u32> {
+        Some(u32::from_be_bytes(self.buf.get(8..12)?.try_into().unwrap()))
+    }
+
+    fn seqno(self: &PacketView<'a>) -> Option<u32> {
+        Some(u32::from_be_bytes(self.buf.get(12..16)?.try_into().unwrap()))
+    }
+
+    fn tlvs(self: &PacketView<'a>) -> Result<TlvIter<'a>, PacketError> {
+        let headerlength = usize::from(self.headerlength()
+            .ok_or(PacketError::UnexpectedEof { needed: HeaderNoTLV::len(), got: self.buf.len() })?);
+        let tlv_len = headerlength.checked_sub(HeaderNoTLV::len())
+            .ok_or(PacketError::HeaderLengthMismatch)?;
+        let buf = self.buf.get(HeaderNoTLV::len()..headerlength)
+            .ok_or(PacketError::UnexpectedEof { needed: headerlength, got: self.buf.len() })?;
+        debug_assert_eq!(buf.len(), tlv_len);
+        Ok(TlvIter { buf })
+    }
+
+    fn payload(self: &PacketView<'a>) -> Result<&'a [u8], PacketError> {
+        let headerlength = usize::from(self.headerlength()
+            .ok_or(PacketError::UnexpectedEof { needed: HeaderNoTLV::len(), got: self.buf.len() })?);
+        let payloadlength = usize::from(self.payloadlength()
+            .ok_or(PacketError::UnexpectedEof { needed: HeaderNoTLV::len(), got: self.buf.len() })?);
+        let framelength = headerlength + payloadlength;
+        self.buf.get(headerlength..framelength)
+            .ok_or(PacketError::UnexpectedEof { needed: framelength, got: self.buf.len() })
+    }
+}
+
 
 
 fn main() {
     println!("Hello, world!");
-    // let tlv = TLV::Timestamp(3u64);
+    // let tlv = TLV::Timestamp(Timestamp::from_48bits(3));
     // let header_no_tlv = HeaderNoTLV{
     //     version: 1u8,
     //     headerlength: 0u8,
@@ -242,24 +684,24 @@ fn main() {
     //     seqno: 0u32.into()
     // };
     // let header = Header{ header_no_tlv,
-    // tlvs: vec![TLV::Timestamp(3), TLV::Null, TLV::Payloadshape([0u16,8,0])]};
+    // tlvs: vec![TLV::Timestamp(Timestamp::from_48bits(3)), TLV::Null, TLV::Payloadshape([0u16,8,0])]};
     // println!("header {:?}",header.as_bytes());
     // let buf = header.as_bytes();
     // let (header_no_tlv2, rest) = HeaderNoTLV::from_bytes(&buf).unwrap();
     // println!("header_no_tlv {:?}", header_no_tlv.as_bytes());
     // println!("header_no_tlv2 {:?}", header_no_tlv2.as_bytes());
     // assert!(header_no_tlv2.headerlength == u8::try_from(header.as_bytes().len()).unwrap());
-    // let (tlv, _) = TLV::try_from_bytes(&TLV::Timestamp(3).as_bytes());
+    // let (tlv, _) = TLV::try_from_bytes(&TLV::Timestamp(Timestamp::from_48bits(3)).as_bytes());
     // let tlv = tlv.unwrap();
     // println!("read this tlv {:?}", tlv);
     // let header2 = Header::from_bytes(&header.as_bytes());
     // println!("header2 {:?}", header2.as_bytes());
     // assert!(header2.as_bytes() == header.as_bytes())
-    let tlvs = vec![TLV::Timestamp(3), TLV::Null, TLV::Payloadshape([0u16,8,0])];
-    let mut maker = PacketMaker::new(0, tlvs);
-    println!("header {:x?}",maker.make(vec![255u8]).as_bytes());
-    println!("header {:x?}",maker.make(vec![255u8]).as_bytes());
-    println!("header {:x?}",maker.make(vec![255u8]).as_bytes());
+    let tlvs = vec![TLV::Timestamp(Timestamp::from_48bits(3)), TLV::Null, TLV::Payloadshape([0u16,8,0])];
+    let mut maker = PacketMaker::new(0, tlvs, true);
+    println!("header {:x?}",maker.make(vec![255u8], &[]).unwrap().as_bytes());
+    println!("header {:x?}",maker.make(vec![255u8], &[]).unwrap().as_bytes());
+    println!("header {:x?}",maker.make(vec![255u8], &[]).unwrap().as_bytes());
 
 
    
@@ -269,14 +711,59 @@ fn main() {
 
 #[test]
 fn test_tlv_to_bytes() {
-    let tlv = TLV::Timestamp(3u64);
-    assert!(tlv.as_bytes() == [0x11, 1,0,0,0,0,0,3]);
+    let tlv = TLV::Timestamp(Timestamp::from_48bits(3));
+    assert!(tlv.as_bytes().unwrap() == [0x11, 1,0,0,0,0,0,3]);
     let tlv = TLV::Payloadshape([0u16,8,0]);
-    assert!(tlv.as_bytes() == [0x22,1,0,0,0,8,0,0]);
+    assert!(tlv.as_bytes().unwrap() == [0x22,1,0,0,0,8,0,0]);
     let tlv = TLV::Null;
-    assert!(tlv.as_bytes() == [0x0,1,0,0,0,0,0,0]);
+    assert!(tlv.as_bytes().unwrap() == [0x0,1,0,0,0,0,0,0]);
     let tlv = TLV::ChannelOffset(16);
-    assert!(tlv.as_bytes() == [0x23,1,0,0,0,0,0,16u8]);
+    assert!(tlv.as_bytes().unwrap() == [0x23,1,0,0,0,0,0,16u8]);
+}
+
+#[test]
+fn test_multi_unit_tlv_roundtrip() {
+    let tlv = TLV::PayloadLabel6Char(b"hello!".to_vec());
+    assert!(tlv.as_bytes().unwrap() == [0x29, 1, b'h', b'e', b'l', b'l', b'o', b'!']);
+
+    let tlv = TLV::PayloadLabel6Char(b"a much longer label".to_vec());
+    let bytes = tlv.as_bytes().unwrap();
+    assert!(bytes[0] == 0x29);
+    assert!(bytes[1] == 3); // ceil((19+2)/8)
+    assert!(bytes.len() == 24);
+    let (decoded, rest) = TLV::try_from_bytes(&bytes).unwrap();
+    assert!(rest.is_empty());
+    match decoded.unwrap() {
+        TLV::PayloadLabel6Char(data) => assert!(&data[..19] == b"a much longer label"),
+        other => panic!("expected PayloadLabel6Char, got {:?}", other),
+    }
+
+    // a value too large for the 1-byte len8bytes field is rejected rather
+    // than silently truncated
+    let value_len = 255 * 8 - 1; // one byte past the 2038-byte budget
+    let tlv = TLV::PayloadLabel6Char(vec![0u8; value_len]);
+    assert_eq!(tlv.as_bytes(), Err(PacketError::TlvValueTooLong(value_len)));
+}
+
+#[test]
+fn test_aggregate_header_length_overflow() {
+    // the TLV is well within the 2038-byte len8bytes budget, but its 256
+    // bytes push headerlength (16 + sum of TLV lengths) past u8::MAX
+    let label = TLV::PayloadLabel6Char(vec![b'x'; 250]);
+    let headerlength = HeaderNoTLV::len() + label.len().unwrap();
+    assert_eq!(
+        Header{ header_no_tlv: HeaderNoTLV{
+            version: HEADER_VERSION,
+            headerlength: 0,
+            payloadlength: 0u16.into(),
+            magic: HEADER_MAGIC.into(),
+            srcid: 0u32.into(),
+            seqno: 0u32.into(),
+        }, tlvs: vec![label] }.as_bytes(),
+        Err(PacketError::HeaderTooLong(headerlength)));
+
+    let mut maker = PacketMaker::new(0, vec![TLV::PayloadLabel6Char(vec![b'x'; 250])], false);
+    assert_eq!(maker.make(vec![], &[]), Err(PacketError::HeaderTooLong(headerlength)));
 }
 
 #[test]
@@ -291,7 +778,180 @@ fn test_header_to_bytes() {
     };
     assert!(header_no_tlv.as_bytes() == [0x1, 0, 0, 0, 0x81, 0x0b, 0, 0xff, 0, 0, 0, 0, 0, 0, 0, 0])   ;
     let header = Header{ header_no_tlv,
-        tlvs: vec![TLV::Timestamp(3), TLV::Null, TLV::Payloadshape([0u16,8,0])]}; 
-    assert!(header.as_bytes() == [1u8, 40, 0, 0, 129, 11, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0, 
+        tlvs: vec![TLV::Timestamp(Timestamp::from_48bits(3)), TLV::Null, TLV::Payloadshape([0u16,8,0])]};
+    assert!(header.as_bytes().unwrap() == [1u8, 40, 0, 0, 129, 11, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0,
     17, 1, 0, 0, 0, 0, 0, 3, 0, 1, 0, 0, 0, 0, 0, 0, 34, 1, 0, 0, 0, 8, 0, 0]);
+    let header2 = Header::from_bytes(&header.as_bytes().unwrap()).unwrap();
+    assert!(header2.as_bytes().unwrap() == header.as_bytes().unwrap());
+}
+
+#[test]
+fn test_decode_errors() {
+    assert_eq!(TLV::try_from_bytes(&[0x11, 1, 0, 0]), Err(PacketError::UnexpectedEof { needed: 8, got: 4 }));
+    assert_eq!(TLV::try_from_bytes(&[0xaa, 1, 0, 0, 0, 0, 0, 0]), Err(PacketError::UnknownTag(0xaa)));
+    assert_eq!(TLV::try_from_bytes(&[0x29, 2, 0, 0, 0, 0, 0, 0]), Err(PacketError::UnexpectedEof { needed: 16, got: 8 }));
+    assert_eq!(TLV::try_from_bytes(&[0x11, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), Err(PacketError::UnsupportedTlvLength(2)));
+    assert_eq!(Header::from_bytes(&[1u8, 2, 0, 0, 129, 11, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0]),
+        Err(PacketError::HeaderLengthMismatch));
+    assert_eq!(Header::from_bytes(&[1u8, 40, 0, 0, 129, 11, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0]),
+        Err(PacketError::UnexpectedEof { needed: 24, got: 0 }));
+}
+
+#[test]
+fn test_decoder() {
+    let mut dec = Decoder::new(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a]);
+    assert_eq!(dec.decode_u8(), Some(0x01));
+    assert_eq!(dec.decode_u16be(), Some(0x0203));
+    assert_eq!(dec.decode_u32be(), Some(0x04050607));
+    assert_eq!(dec.skip(1), Some(()));
+    assert_eq!(dec.decode_remainder(), &[0x09, 0x0a]);
+    assert_eq!(dec.decode_u8(), None);
+
+    let mut short = Decoder::new(&[0xff]);
+    assert_eq!(short.decode_u16be(), None);
+    assert_eq!(short.decode_u8(), Some(0xff)); // the failed read above didn't advance offset
+}
+
+#[test]
+fn test_incremental_decoder_reassembles_fragments() {
+    let tlvs = vec![TLV::Timestamp(Timestamp::from_48bits(3)), TLV::Null, TLV::Payloadshape([0u16,8,0])];
+    let mut maker = PacketMaker::new(0, tlvs, false);
+    let packet = maker.make(vec![0xaa, 0xbb], &[]).unwrap();
+
+    let mut dec = IncrementalDecoder::new();
+    assert_eq!(dec.feed(&packet[..10]).unwrap(), None);
+    assert_eq!(dec.feed(&packet[10..packet.len()-1]).unwrap(), None);
+    let (header, payload) = dec.feed(&packet[packet.len()-1..]).unwrap().unwrap();
+    assert!(header.tlvs == vec![TLV::Timestamp(Timestamp::from_48bits(3)), TLV::Null, TLV::Payloadshape([0u16,8,0])]);
+    assert!(payload == Bytes::from_static(&[0xaa, 0xbb]));
+
+    // leftover bytes from a second frame are retained for the next feed
+    let packet2 = maker.make(vec![0xcc], &[]).unwrap();
+    assert_eq!(dec.feed(&packet2[..packet2.len()-1]).unwrap(), None);
+    let (_header2, payload2) = dec.feed(&packet2[packet2.len()-1..]).unwrap().unwrap();
+    assert!(payload2 == Bytes::from_static(&[0xcc]));
+}
+
+#[test]
+fn test_incremental_decoder_resyncs_after_malformed_header() {
+    // headerlength=0, payloadlength=0 declares a frame shorter than the
+    // preamble that produced it; there's no valid frame boundary to wait
+    // for, so feed must drop a byte and error instead of wedging forever
+    let mut dec = IncrementalDecoder::new();
+    assert_eq!(dec.feed(&[0u8; 20]), Err(PacketError::HeaderLengthMismatch));
+    // every subsequent feed drops one more byte and errors again, instead
+    // of repeating forever on the same buffered bytes
+    for _ in 0..4 {
+        assert_eq!(dec.feed(&[]), Err(PacketError::HeaderLengthMismatch));
+    }
+    // once fewer than a header's worth of bytes remain, feed waits for more
+    // input instead of erroring
+    assert_eq!(dec.feed(&[]), Ok(None));
+}
+
+#[test]
+fn test_timestamp_roundtrip() {
+    let ts = Timestamp::from_unix_nanos(1_700_000_000_123_456_789).unwrap();
+    assert_eq!(ts.seconds, 1_700_000_000);
+    // sub-second resolution is 1/65536s (~15.3us), so nanos round-trip only approximately
+    assert!(ts.to_unix_nanos().abs_diff(1_700_000_000_123_456_789) < 20_000);
+
+    assert_eq!(Timestamp::from_unix_nanos(u64::MAX), Err(PacketError::TimestampOverflow(u64::MAX)));
+
+    let ts = Timestamp { seconds: 0x01020304, subseconds: 0x0506 };
+    assert_eq!(ts.to_48bits(), 0x010203040506);
+    assert_eq!(Timestamp::from_48bits(0x010203040506), ts);
+}
+
+#[test]
+fn test_make_appends_dynamic_tlvs_then_timestamp() {
+    let mut maker = PacketMaker::new(0, vec![TLV::Null], true);
+    let packet = maker.make(vec![], &[TLV::ChannelOffset(7)]).unwrap();
+    let header = Header::from_bytes(&packet).unwrap();
+    assert_eq!(header.tlvs.len(), 3);
+    assert_eq!(header.tlvs[0], TLV::Null);
+    assert_eq!(header.tlvs[1], TLV::ChannelOffset(7));
+    assert!(matches!(header.tlvs[2], TLV::Timestamp(_)));
+}
+
+#[test]
+fn test_packet_reader_parse() {
+    let mut maker = PacketMaker::new(42, vec![TLV::Null], false);
+    let packet = maker.make(vec![1, 2, 3], &[]).unwrap();
+
+    let (header, payload) = PacketReader::parse(&packet).unwrap();
+    assert_eq!(header.header_no_tlv.srcid.get(), 42);
+    assert_eq!(payload, &[1, 2, 3]);
+
+    // trailing garbage after the declared frame is ignored, not included in the payload
+    let mut with_garbage = packet.to_vec();
+    with_garbage.extend_from_slice(&[0xff, 0xff]);
+    let (_, payload) = PacketReader::parse(&with_garbage).unwrap();
+    assert_eq!(payload, &[1, 2, 3]);
+
+    let mut bad_magic = packet.to_vec();
+    bad_magic[4] = 0;
+    assert_eq!(PacketReader::parse(&bad_magic), Err(PacketError::BadMagic(0x000b00ff)));
+
+    let mut bad_version = packet.to_vec();
+    bad_version[0] = 2;
+    assert_eq!(PacketReader::parse(&bad_version), Err(PacketError::UnsupportedVersion(2)));
+
+    let truncated = &packet[..packet.len()-1];
+    assert_eq!(PacketReader::parse(truncated),
+        Err(PacketError::UnexpectedEof { needed: packet.len(), got: packet.len()-1 }));
+}
+
+#[test]
+fn test_seqno_tracker() {
+    let mut tracker = SeqnoTracker::new();
+    assert_eq!(tracker.check_seqno(1, 0), SeqnoStatus::InOrder);
+    assert_eq!(tracker.check_seqno(1, 1), SeqnoStatus::InOrder);
+    assert_eq!(tracker.check_seqno(1, 1), SeqnoStatus::Duplicate);
+    assert_eq!(tracker.check_seqno(1, 5), SeqnoStatus::Gap { missed: 3 });
+    // a separate srcid tracks its own sequence independently
+    assert_eq!(tracker.check_seqno(2, 0), SeqnoStatus::InOrder);
+
+    // seqno wraps around u32::MAX the same way it wraps on any other gap
+    let mut wrapping = SeqnoTracker::new();
+    assert_eq!(wrapping.check_seqno(3, u32::MAX - 2), SeqnoStatus::InOrder);
+    assert_eq!(wrapping.check_seqno(3, 0), SeqnoStatus::Gap { missed: 2 });
+    assert_eq!(wrapping.check_seqno(3, u32::MAX - 2), SeqnoStatus::Duplicate);
+}
+
+#[test]
+fn test_packet_view_accessors_and_tlv_iter() {
+    let mut maker = PacketMaker::new(7, vec![TLV::Timestamp(Timestamp::from_48bits(3)), TLV::ChannelOffset(9)], false);
+    let packet = maker.make(vec![0xaa, 0xbb, 0xcc], &[]).unwrap();
+
+    let view = PacketView::new(&packet);
+    assert_eq!(view.version(), Some(HEADER_VERSION));
+    assert_eq!(view.magic(), Some(HEADER_MAGIC));
+    assert_eq!(view.srcid(), Some(7));
+    assert_eq!(view.seqno(), Some(0));
+    assert_eq!(view.payload().unwrap(), &[0xaa, 0xbb, 0xcc]);
+
+    let tlvs: Result<Vec<TlvRef>, PacketError> = view.tlvs().unwrap().collect();
+    let tlvs = tlvs.unwrap();
+    assert_eq!(tlvs.len(), 2);
+    assert_eq!(tlvs[0].tag(), 0x11);
+    assert_eq!(tlvs[1].tag(), 0x23);
+    assert_eq!(tlvs[1].value_bytes(), &[0, 0, 0, 0, 0, 9]);
+}
+
+#[test]
+fn test_packet_view_on_truncated_buffer() {
+    let mut maker = PacketMaker::new(7, vec![TLV::Null], false);
+    let packet = maker.make(vec![0xaa], &[]).unwrap();
+
+    let truncated = &packet[..packet.len()-1];
+    let view = PacketView::new(truncated);
+    // the fixed header is still intact, so these fields are still readable
+    assert_eq!(view.srcid(), Some(7));
+    // but asking for the now-short payload reports an error instead of panicking
+    assert!(matches!(view.payload(), Err(PacketError::UnexpectedEof { .. })));
+
+    let too_short_for_header = &packet[..4];
+    let view = PacketView::new(too_short_for_header);
+    assert_eq!(view.srcid(), None);
 }
\ No newline at end of file